@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use libloading::{Library, Symbol};
+use tree_sitter::Language;
+
+/// A node kind that introduces a named scope (a class, a function, a module)
+/// together with the field name that holds its identifier.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub kind: String,
+    pub name_field: String,
+}
+
+impl Scope {
+    fn new(kind: &str, name_field: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            name_field: name_field.to_string(),
+        }
+    }
+}
+
+/// A tree-sitter grammar plus the metadata trep needs to name scopes in it.
+#[derive(Clone)]
+pub struct Grammar {
+    pub language: Language,
+    pub scopes: Vec<Scope>,
+}
+
+impl Grammar {
+    /// The identifier field name for `kind`, if that kind is a named scope.
+    pub fn name_field(&self, kind: &str) -> Option<&str> {
+        self.scopes
+            .iter()
+            .find(|s| s.kind == kind)
+            .map(|s| s.name_field.as_str())
+    }
+}
+
+/// Maps file extensions to the grammar trep should parse them with.
+///
+/// The registry ships with a handful of built-in grammars but is fully
+/// overridable: callers can `insert` their own extension/grammar pairs, or
+/// `load_dynamic` grammars discovered as shared objects at runtime.
+pub struct GrammarRegistry {
+    by_extension: HashMap<String, Grammar>,
+    // Dynamically loaded libraries are kept alive for the lifetime of the
+    // registry so the `Language` pointers they hand out stay valid.
+    _libraries: Vec<Library>,
+}
+
+impl GrammarRegistry {
+    /// A registry pre-populated with trep's built-in grammars.
+    pub fn new() -> Self {
+        let mut registry = Self {
+            by_extension: HashMap::new(),
+            _libraries: Vec::new(),
+        };
+
+        registry.insert(
+            "py",
+            Grammar {
+                language: tree_sitter_python::language(),
+                scopes: vec![
+                    Scope::new("class_definition", "name"),
+                    Scope::new("function_definition", "name"),
+                ],
+            },
+        );
+        registry.insert(
+            "rs",
+            Grammar {
+                language: tree_sitter_rust::language(),
+                scopes: vec![
+                    Scope::new("function_item", "name"),
+                    Scope::new("impl_item", "type"),
+                    Scope::new("mod_item", "name"),
+                    Scope::new("struct_item", "name"),
+                    Scope::new("enum_item", "name"),
+                    Scope::new("trait_item", "name"),
+                ],
+            },
+        );
+        let javascript = Grammar {
+            language: tree_sitter_javascript::language(),
+            scopes: vec![
+                Scope::new("function_declaration", "name"),
+                Scope::new("class_declaration", "name"),
+                Scope::new("method_definition", "name"),
+            ],
+        };
+        registry.insert("js", javascript.clone());
+        registry.insert("mjs", javascript);
+        registry.insert(
+            "go",
+            Grammar {
+                language: tree_sitter_go::language(),
+                scopes: vec![
+                    Scope::new("function_declaration", "name"),
+                    Scope::new("method_declaration", "name"),
+                    Scope::new("type_declaration", "name"),
+                ],
+            },
+        );
+
+        registry
+    }
+
+    /// Register a grammar for `extension`, replacing any previous entry.
+    pub fn insert(&mut self, extension: &str, grammar: Grammar) {
+        self.by_extension.insert(extension.to_string(), grammar);
+    }
+
+    /// The grammar to use for `path`, based on its file extension.
+    pub fn grammar_for(&self, path: &Path) -> Option<&Grammar> {
+        let extension = path.extension().and_then(|s| s.to_str())?;
+        self.by_extension.get(extension)
+    }
+
+    /// Discover and `dlopen` grammars placed under
+    /// `~/.config/trep/grammars/<lang>.so` (or `$XDG_CONFIG_HOME/trep/grammars`
+    /// when that variable is set), in the manner of Helix's editor grammars. Each library is expected to export a `tree_sitter_<lang>`
+    /// symbol returning its `Language`. The grammar is registered for the file
+    /// extensions that language uses (e.g. `ruby` -> `rb`/`rbw`), falling back
+    /// to the language name itself for grammars we don't have a mapping for.
+    pub fn load_dynamic(&mut self) -> Result<()> {
+        let dir = grammar_dir();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            // A missing grammar directory simply means there is nothing to load.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).context(format!("reading grammar directory {}", dir.display()))
+            }
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("so") {
+                continue;
+            }
+            let lang = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .map(String::from)
+                .with_context(|| format!("grammar {} has no language name", path.display()))?;
+
+            let (language, library) = unsafe { load_language(&path, &lang) }
+                .with_context(|| format!("loading grammar {}", path.display()))?;
+
+            self._libraries.push(library);
+            let grammar = Grammar {
+                language,
+                scopes: Vec::new(),
+            };
+            for extension in extensions_for(&lang) {
+                self.insert(extension, grammar.clone());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The file extensions a language's source uses. Falls back to the language
+/// name itself when we don't have a mapping, so an unknown `foo.so` still
+/// matches `*.foo` files.
+fn extensions_for(lang: &str) -> Vec<&str> {
+    match lang {
+        "python" => vec!["py", "pyw"],
+        "rust" => vec!["rs"],
+        "javascript" => vec!["js", "mjs", "cjs"],
+        "typescript" => vec!["ts"],
+        "go" => vec!["go"],
+        "ruby" => vec!["rb", "rbw"],
+        "c" => vec!["c", "h"],
+        "cpp" => vec!["cpp", "cc", "cxx", "hpp", "hh"],
+        "java" => vec!["java"],
+        other => vec![other],
+    }
+}
+
+impl Default for GrammarRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `dlopen` `path` and call its `tree_sitter_<lang>` constructor.
+///
+/// # Safety
+///
+/// The library must export an `extern "C" fn() -> Language` named
+/// `tree_sitter_<lang>`, and the returned pointer must remain valid for as
+/// long as the returned [`Library`] is kept alive.
+unsafe fn load_language(path: &Path, lang: &str) -> Result<(Language, Library)> {
+    let library = Library::new(path).context("opening shared object")?;
+    let symbol_name = format!("tree_sitter_{lang}");
+    let language = {
+        let constructor: Symbol<unsafe extern "C" fn() -> Language> = library
+            .get(symbol_name.as_bytes())
+            .with_context(|| format!("grammar is missing symbol {symbol_name}"))?;
+        constructor()
+    };
+    Ok((language, library))
+}
+
+fn grammar_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME") {
+        return PathBuf::from(dir).join("trep").join("grammars");
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".config").join("trep").join("grammars")
+}
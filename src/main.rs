@@ -1,90 +1,343 @@
 use anyhow::Context;
 use anyhow::Result;
-use std::{error::Error, path::Path};
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 
 use clap::Parser;
 use ignore::WalkBuilder;
-use tree_sitter::{Node, Tree};
+use rayon::prelude::*;
+use regex::Regex;
+use tree_sitter::Node;
+
+// ANSI escapes used to highlight the matched span in a printed block.
+const HIGHLIGHT: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+mod grammar;
+
+use grammar::{Grammar, GrammarRegistry};
+
+thread_local! {
+    // A `tree_sitter::Parser` cannot be shared across threads, so give each
+    // rayon worker its own, reused across the files it handles.
+    static PARSER: RefCell<tree_sitter::Parser> = RefCell::new(tree_sitter::Parser::new());
+}
 
 #[derive(Debug, Parser)]
 struct Cli {
+    /// A tree-sitter S-expression query to match structurally instead of by
+    /// text, e.g. `(call function: (identifier) @fn)`.
+    #[arg(long)]
+    query: Option<String>,
+
+    /// Emit one JSON object per match instead of the human-readable format.
+    #[arg(long)]
+    json: bool,
+
+    /// Only count matches whose leaf or an enclosing node has one of these
+    /// node kinds (e.g. `--in comment`, `--in string`). Repeatable.
+    #[arg(long = "in")]
+    in_kinds: Vec<String>,
+
+    /// Skip matches whose leaf or an enclosing node has one of these node
+    /// kinds (e.g. `--not-in string`). Repeatable.
+    #[arg(long = "not-in")]
+    not_in_kinds: Vec<String>,
+
     // take in pattern as last argument with no default
-    pattern: String,
+    pattern: Option<String>,
+}
+
+/// Restricts which leaves count as matches based on their node kind and the
+/// kinds of their enclosing scopes.
+struct ScopeFilter {
+    allowed: Vec<String>,
+    denied: Vec<String>,
+}
+
+/// The per-file context shared by the search and reporting routines: the
+/// source text, its path, the grammar it was parsed with, and how to render
+/// matches.
+struct Ctx<'a> {
+    source_code: &'a str,
+    fname: &'a Path,
+    grammar: &'a Grammar,
+    json: bool,
+    color: bool,
+}
+
+impl ScopeFilter {
+    /// Whether `node` satisfies the filter, consulting its own kind and the
+    /// kinds of every ancestor on its parent chain.
+    fn matches(&self, node: Node) -> bool {
+        if self.allowed.is_empty() && self.denied.is_empty() {
+            return true;
+        }
+        let kinds: Vec<&str> = collect_parent_hierarchy(node)
+            .iter()
+            .map(|n| n.kind())
+            .collect();
+        if self.denied.iter().any(|d| kinds.contains(&d.as_str())) {
+            return false;
+        }
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|a| kinds.contains(&a.as_str())) {
+            return false;
+        }
+        true
+    }
 }
 
 fn main() -> Result<()> {
     // Parse the command line arguments
     let args = Cli::parse();
-    let pattern = args.pattern;
 
-    let mut parser = tree_sitter::Parser::new();
-    let language = tree_sitter_python::language();
-    parser
-        .set_language(language)
-        .expect("Error loading Python grammar");
+    let mut registry = GrammarRegistry::new();
+    registry.load_dynamic()?;
+
+    let filter = ScopeFilter {
+        allowed: args.in_kinds.clone(),
+        denied: args.not_in_kinds.clone(),
+    };
 
-    // Walk the current directory
+    // Compile the text pattern as a regex up front; `--query` mode ignores it.
+    let regex = match &args.pattern {
+        Some(pattern) => Some(Regex::new(pattern).context("invalid regex pattern")?),
+        None => None,
+    };
+
+    // Highlight matches only when writing to a terminal (and not as JSON).
+    let color = !args.json && std::io::stdout().is_terminal();
+
+    // Collect the file list up front so we can fan the parsing out across
+    // cores and still emit results in a deterministic order.
+    let mut paths: Vec<PathBuf> = Vec::new();
     for result in WalkBuilder::new("./").build() {
         let entry = result?;
-        let path = entry.path();
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("py") {
-            // Read the file content
-            let source_code = std::fs::read_to_string(path).expect("Error reading file");
-
-            // Process the file with tree-sitter
-            let tree = parser
-                .parse(&source_code, None)
-                .context("Error parsing source code")?;
-            let root_node = tree.root_node();
-            process_file(&root_node, &source_code, path, &pattern)?;
+        if entry.path().is_file() {
+            paths.push(entry.into_path());
         }
     }
 
+    // Parse and search each file on a rayon worker, buffering its output.
+    let mut outputs: Vec<(PathBuf, String)> = paths
+        .into_par_iter()
+        .map(|path| -> Result<Option<(PathBuf, String)>> {
+            // Pick the grammar by file extension; skip files we don't understand.
+            let Some(grammar) = registry.grammar_for(&path) else {
+                return Ok(None);
+            };
+
+            // Skip files we can't read as UTF-8 (binaries, other encodings)
+            // instead of aborting the whole search.
+            let Ok(source_code) = std::fs::read_to_string(&path) else {
+                return Ok(None);
+            };
+
+            let buffer = PARSER.with(|parser| -> Result<String> {
+                let mut parser = parser.borrow_mut();
+                parser
+                    .set_language(grammar.language)
+                    .expect("Error loading grammar");
+                let tree = parser
+                    .parse(&source_code, None)
+                    .context("Error parsing source code")?;
+                let root_node = tree.root_node();
+
+                let ctx = Ctx {
+                    source_code: &source_code,
+                    fname: &path,
+                    grammar,
+                    json: args.json,
+                    color,
+                };
+
+                let mut out = String::new();
+                if let Some(query) = &args.query {
+                    process_query(&root_node, query, &ctx, &mut out)?;
+                } else {
+                    let regex = regex
+                        .as_ref()
+                        .context("a search pattern or --query is required")?;
+                    process_file(&root_node, regex, &filter, &ctx, &mut out)?;
+                }
+                Ok(out)
+            })?;
+
+            Ok(Some((path, buffer)))
+        })
+        .filter_map(|r| r.transpose())
+        .collect::<Result<Vec<_>>>()?;
+
+    // Emit in path order so parallel execution never interleaves files.
+    outputs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (_, buffer) in outputs {
+        print!("{buffer}");
+    }
+
     Ok(())
 }
 
-fn process_file(root_node: &Node, source_code: &str, fname: &Path, pattern: &str) -> Result<()> {
-    let matched_nodes = find_leaf_nodes_with_text(*root_node, pattern, source_code)?;
-    if matched_nodes.is_empty() {
-        return Ok(());
-    }
+fn process_file(
+    root_node: &Node,
+    regex: &Regex,
+    filter: &ScopeFilter,
+    ctx: &Ctx,
+    out: &mut String,
+) -> Result<()> {
+    let matched_nodes = find_leaf_nodes_with_text(*root_node, regex, ctx.source_code, filter)?;
     for matched_node in matched_nodes {
-        let hierarchy = collect_parent_hierarchy(matched_node);
-
-        let nodes_with_names: Vec<(Node, String)> = hierarchy
-            .iter()
-            .filter_map(|n| get_node_name(*n, source_code).map(|name| (*n, name)))
+        // Map each regex hit inside the leaf to an absolute source byte range
+        // so it can be highlighted within the printed block.
+        let text = matched_node.utf8_text(ctx.source_code.as_bytes())?;
+        let spans: Vec<(usize, usize)> = regex
+            .find_iter(text)
+            .map(|m| {
+                (
+                    matched_node.start_byte() + m.start(),
+                    matched_node.start_byte() + m.end(),
+                )
+            })
             .collect();
+        report_match(matched_node, ctx, None, &spans, out);
+    }
+    Ok(())
+}
 
-        let last_node = nodes_with_names.last().map(|(n, _)| *n).unwrap();
+/// Run a tree-sitter S-expression query over a file and report every capture.
+///
+/// Standard text predicates (`#eq?`, `#match?`, ...) are evaluated against the
+/// source text we hand the cursor as its text provider, so a capture is only
+/// reported when its match satisfies them. Each capture is reported with the
+/// same `class->function` hierarchy and block-printing machinery as a text
+/// search.
+fn process_query(
+    root_node: &Node,
+    query_src: &str,
+    ctx: &Ctx,
+    out: &mut String,
+) -> Result<()> {
+    let query = tree_sitter::Query::new(ctx.grammar.language, query_src)
+        .map_err(|e| anyhow::anyhow!("invalid query: {e:?}"))?;
+    let capture_names = query.capture_names();
 
-        let hierarchy_str = hierarchy
-            .iter()
-            .filter_map(|n| get_node_name(*n, source_code))
-            .collect::<Vec<_>>()
-            .join("->");
-
-        let line = matched_block(matched_node, last_node, source_code);
-        println!("{} {}: {}", fname.to_string_lossy(), hierarchy_str, line);
+    let mut cursor = tree_sitter::QueryCursor::new();
+    let source_bytes = ctx.source_code.as_bytes();
+    // Feeding the cursor a text provider is what drives `#eq?`/`#match?`
+    // evaluation; without it every structural match would be yielded.
+    let text_provider = |node: Node| std::iter::once(&source_bytes[node.byte_range()]);
+    for m in cursor.matches(&query, *root_node, text_provider) {
+        for capture in m.captures {
+            let name = capture_names[capture.index as usize].as_str();
+            report_match(capture.node, ctx, Some(name), &[], out);
+        }
     }
     Ok(())
 }
 
+/// Print a single matched node with its named-scope hierarchy and block.
+///
+/// `capture` is the query capture name (`@name`) when the match came from a
+/// structural query, and `None` for a plain text search.
+fn report_match(
+    matched_node: Node,
+    ctx: &Ctx,
+    capture: Option<&str>,
+    spans: &[(usize, usize)],
+    out: &mut String,
+) {
+    let hierarchy = collect_parent_hierarchy(matched_node);
+
+    let nodes_with_names: Vec<(Node, String)> = hierarchy
+        .iter()
+        .filter_map(|n| get_node_name(*n, ctx.source_code, ctx.grammar).map(|name| (*n, name)))
+        .collect();
+
+    // Fall back to the matched node itself when it lives outside any named
+    // scope (e.g. a top-level statement or query capture).
+    let last_node = nodes_with_names
+        .last()
+        .map(|(n, _)| *n)
+        .unwrap_or(matched_node);
+
+    let names: Vec<String> = nodes_with_names
+        .iter()
+        .map(|(_, name)| name.clone())
+        .collect();
+
+    // Positions are 1-based to match editor/ripgrep conventions.
+    let start = matched_node.start_position();
+    let end = matched_node.end_position();
+    let block = matched_block(matched_node, last_node, ctx.source_code, spans, ctx.color);
+    let path = ctx.fname.to_string_lossy();
+
+    // Writing to a `String` is infallible; the `let _` keeps clippy quiet.
+    if ctx.json {
+        let record = MatchRecord {
+            path: &path,
+            start_line: start.row + 1,
+            start_col: start.column + 1,
+            end_line: end.row + 1,
+            end_col: end.column + 1,
+            start_byte: matched_node.start_byte(),
+            end_byte: matched_node.end_byte(),
+            hierarchy: &names,
+            capture,
+            block: &block,
+        };
+        let _ = writeln!(
+            out,
+            "{}",
+            serde_json::to_string(&record).expect("serializing match record")
+        );
+        return;
+    }
+
+    let hierarchy_str = names.join("->");
+    let location = format!("{}:{}:{}", path, start.row + 1, start.column + 1);
+    match capture {
+        Some(name) => {
+            let _ = writeln!(out, "{location}: {hierarchy_str} @{name}: {block}");
+        }
+        None => {
+            let _ = writeln!(out, "{location}: {hierarchy_str}: {block}");
+        }
+    }
+}
+
+/// A single match rendered for `--json` consumers (editors, scripts).
+#[derive(serde::Serialize)]
+struct MatchRecord<'a> {
+    path: &'a str,
+    start_line: usize,
+    start_col: usize,
+    end_line: usize,
+    end_col: usize,
+    start_byte: usize,
+    end_byte: usize,
+    hierarchy: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture: Option<&'a str>,
+    block: &'a str,
+}
+
 fn find_leaf_nodes_with_text<'a>(
     node: Node<'a>,
-    search_term: &str,
+    regex: &Regex,
     source_code: &str,
+    filter: &ScopeFilter,
 ) -> Result<Vec<Node<'a>>> {
     let mut matches = Vec::new();
     if node.child_count() == 0 {
         let node_text = node.utf8_text(source_code.as_bytes())?;
-        if node_text.contains(search_term) {
+        if regex.is_match(node_text) && filter.matches(node) {
             matches.push(node);
         }
     } else {
         for i in 0..node.child_count() {
             if let Some(child) = node.child(i) {
-                matches.extend(find_leaf_nodes_with_text(child, search_term, source_code)?);
+                matches.extend(find_leaf_nodes_with_text(child, regex, source_code, filter)?);
             }
         }
     }
@@ -103,26 +356,25 @@ fn collect_parent_hierarchy(node: Node) -> Vec<Node> {
     hierarchy.reverse(); // Reverse to get the hierarchy from root to leaf
     hierarchy
 }
-fn get_node_name(node: Node, source_code: &str) -> Option<String> {
-    match node.kind() {
-        "class_definition" | "function_definition" => node
-            .child_by_field_name("name")
-            .and_then(|n| n.utf8_text(source_code.as_bytes()).ok())
-            .map(String::from),
-        _ => None,
-    }
+fn get_node_name(node: Node, source_code: &str, grammar: &Grammar) -> Option<String> {
+    let name_field = grammar.name_field(node.kind())?;
+    node.child_by_field_name(name_field)
+        .and_then(|n| n.utf8_text(source_code.as_bytes()).ok())
+        .map(String::from)
 }
 
-fn matched_block(node: Node, last_node: Node, source_code: &str) -> String {
+fn matched_block(
+    node: Node,
+    last_node: Node,
+    source_code: &str,
+    spans: &[(usize, usize)],
+    color: bool,
+) -> String {
     // TODO we want to find the highest level node that we printed out previously and then report
 
     // Find the topmost relevant node for the pattern (e.g., the enclosing function or class)
     let mut relevant_node = node;
-    loop {
-        let Some(parent) = relevant_node.parent() else {
-            break;
-        };
-
+    while let Some(parent) = relevant_node.parent() {
         let Some(grandparent) = parent.parent() else {
             break;
         };
@@ -138,8 +390,38 @@ fn matched_block(node: Node, last_node: Node, source_code: &str) -> String {
     let end_byte = relevant_node.end_byte();
     let block = &source_code[start_byte..end_byte];
 
+    // Wrap each matched span in ANSI color before collapsing whitespace, so
+    // the hit stands out inside the printed block.
+    let block = if color {
+        highlight(block, start_byte, spans)
+    } else {
+        block.to_string()
+    };
+
     // remove newlines
-    format_multiline(block)
+    format_multiline(&block)
+}
+
+/// Insert ANSI highlight escapes around each span that falls within the block
+/// starting at `block_start` in the source. `spans` hold absolute source byte
+/// offsets; they are applied right-to-left so earlier offsets stay valid.
+fn highlight(block: &str, block_start: usize, spans: &[(usize, usize)]) -> String {
+    let mut spans: Vec<(usize, usize)> = spans
+        .iter()
+        .filter_map(|&(s, e)| {
+            let s = s.checked_sub(block_start)?;
+            let e = e.checked_sub(block_start)?;
+            (e <= block.len()).then_some((s, e))
+        })
+        .collect();
+    spans.sort_by_key(|&(start, _)| std::cmp::Reverse(start));
+
+    let mut out = block.to_string();
+    for (start, end) in spans {
+        out.insert_str(end, RESET);
+        out.insert_str(start, HIGHLIGHT);
+    }
+    out
 }
 
 